@@ -4,8 +4,10 @@
 use clap::{
     Parser, Subcommand
 };
-use crate::data::{
-    Round, Region
+use crate::{
+    data::{
+        Round, Region
+    }, genetic::SelectionKind
 };
 
 #[derive(Parser)]
@@ -19,7 +21,31 @@ pub struct CliArgs {
 #[derive(Subcommand)]
 pub enum PredictorCommands {
     /// Train on the data set .csv
-    Train,
+    Train {
+        /// Train for this many seconds instead of running to a fixed/plateau-based generation
+        /// limit, using a simulated-annealing-style mutation schedule
+        #[arg(long)]
+        timed_secs: Option<f64>,
+
+        /// Disable the per-generation fitness cache and re-evaluate every individual, even ones
+        /// carried over unchanged. Useful for deterministic benchmarking.
+        #[arg(long)]
+        no_cache: bool,
+
+        /// Parent-picking strategy used to fill out each new generation
+        #[arg(long, value_enum, default_value_t = SelectionKind::Tournament)]
+        selection: SelectionKind,
+
+        /// Learning rate for the optional memetic backpropagation refinement step.
+        /// Not applied under --timed-secs
+        #[arg(long, default_value_t = 0.01)]
+        refine_lr: f64,
+
+        /// Number of backpropagation iterations to polish the top networks with each generation.
+        /// 0 (the default) disables memetic refinement entirely. Not applied under --timed-secs
+        #[arg(long, default_value_t = 0)]
+        refine_steps: usize
+    },
 
     /// Use a model to predict wins
     Predict {
@@ -30,6 +56,16 @@ pub enum PredictorCommands {
         high_seed_team: String,
         low_seed: u8,
         low_seed_team: String
+    },
+
+    /// Monte Carlo simulate a full 64-team bracket, reporting each team's advancement odds
+    Bracket {
+        /// CSV of region/seed/name rows, 16 teams per region, listed in true bracket order
+        seed_file: String,
+
+        /// Number of full-tournament trials to run
+        #[arg(long, default_value_t = 10_000)]
+        trials: usize
     }
 }
 
@@ -0,0 +1,164 @@
+/*
+ * Author: Dylan Turner
+ * Description: Monte Carlo simulation of a full 64-team bracket using a trained model
+ */
+
+use std::{
+    collections::HashMap,
+    error::Error
+};
+use rand::{
+    thread_rng, Rng
+};
+use crate::{
+    data::{
+        name_to_chars, Game, RawGameInfo, Region, Round, SeedEntry
+    },
+    network::Network
+};
+
+/// How sharply a predicted score margin turns into a win probability:
+/// win_prob = logistic(margin / MARGIN_SCALE)
+const MARGIN_SCALE: f64 = 10.0;
+
+/// The rounds played within a single region, in order, halving the field of 16 down to 1
+const REGIONAL_ROUNDS: [Round; 4] = [
+    Round::RoundOf64, Round::RoundOf32, Round::Sweet16, Round::Elite8
+];
+
+#[derive(Clone)]
+struct Team {
+    name: String,
+    seed: u8
+}
+
+/*
+ * Run `trials` Monte Carlo simulations of the full tournament described by `seed_fname` (a CSV
+ * of region/seed/name rows, 16 teams per region, listed in true bracket order) and print each
+ * team's advancement odds, sorted by championship odds.
+ */
+pub async fn simulate(
+        model_fname: &str, seed_fname: &str, trials: usize) -> Result<(), Box<dyn Error>> {
+    let entries = SeedEntry::vec_from_file(seed_fname)?;
+    let predictor = Network::from_file(model_fname)?;
+
+    let mut regions: Vec<(Region, Vec<Team>)> = Vec::new();
+    for entry in entries {
+        let idx = match regions.iter().position(|(region, _)| *region == entry.region) {
+            Some(idx) => idx,
+            None => {
+                regions.push((entry.region, Vec::new()));
+                regions.len() - 1
+            }
+        };
+        regions[idx].1.push(Team {
+            name: entry.name,
+            seed: entry.seed
+        });
+    }
+
+    if regions.len() != 4 {
+        Err(format!(
+            "Seed file {} must describe exactly 4 regions, found {}", seed_fname, regions.len()
+        ))?;
+    }
+    for (region, teams) in &regions {
+        if teams.len() != 16 {
+            Err(format!(
+                "Region {:?} in {} must have 16 teams, found {}", region, seed_fname, teams.len()
+            ))?;
+        }
+    }
+
+    let mut sweet16_count: HashMap<String, u64> = HashMap::new();
+    let mut final4_count: HashMap<String, u64> = HashMap::new();
+    let mut champion_count: HashMap<String, u64> = HashMap::new();
+
+    for _ in 0..trials {
+        let mut regional_winners = Vec::with_capacity(regions.len());
+        for (region, field) in regions.iter() {
+            let mut teams = field.clone();
+            for (i, round) in REGIONAL_ROUNDS.iter().enumerate() {
+                teams = play_round(&predictor, &teams, *round, Some(*region))?;
+                if i == 1 {
+                    for team in &teams {
+                        *sweet16_count.entry(team.name.clone()).or_insert(0) += 1;
+                    }
+                }
+            }
+            regional_winners.push(teams.remove(0));
+        }
+
+        for team in &regional_winners {
+            *final4_count.entry(team.name.clone()).or_insert(0) += 1;
+        }
+
+        let mut final_four = regional_winners;
+        final_four = play_round(&predictor, &final_four, Round::Semifinals, None)?;
+        final_four = play_round(&predictor, &final_four, Round::Championship, None)?;
+        *champion_count.entry(final_four[0].name.clone()).or_insert(0) += 1;
+    }
+
+    let mut all_teams: Vec<&str> = regions.iter()
+        .flat_map(|(_, teams)| teams.iter().map(|team| team.name.as_str()))
+        .collect();
+    all_teams.sort_by(|a, b| {
+        champion_count.get(*b).unwrap_or(&0).cmp(champion_count.get(*a).unwrap_or(&0))
+    });
+
+    println!("team\tsweet16%\tfinal4%\tchampion%");
+    for name in all_teams {
+        let pct = |counts: &HashMap<String, u64>| {
+            100.0 * (*counts.get(name).unwrap_or(&0) as f64) / (trials as f64)
+        };
+        println!("{}\t{:.1}\t{:.1}\t{:.1}", name, pct(&sweet16_count), pct(&final4_count), pct(&champion_count));
+    }
+
+    Ok(())
+}
+
+// Play one round of a field down to its winners, pairing up adjacent teams bracket-order.
+// Pure CPU work with no I/O, so unlike `simulate` this doesn't need to be async
+fn play_round(
+        predictor: &Network, teams: &[Team], round: Round,
+        region: Option<Region>) -> Result<Vec<Team>, Box<dyn Error>> {
+    let mut rng = thread_rng();
+    let mut winners = Vec::with_capacity(teams.len() / 2);
+
+    for pair in teams.chunks(2) {
+        let (high, low) = if pair[0].seed <= pair[1].seed {
+            (&pair[0], &pair[1])
+        } else {
+            (&pair[1], &pair[0])
+        };
+
+        let game = Game {
+            year: ['0', '0'], // Bracket sims aren't tied to a real historical year
+            round,
+            region,
+            winner_seed: high.seed,
+            winner_name: name_to_chars(&high.name),
+            winner_score: 0,
+            loser_seed: low.seed,
+            loser_name: name_to_chars(&low.name),
+            loser_score: 0,
+            overtime: 0
+        };
+        let input = RawGameInfo {
+            input_bits: game.to_input_bits()?.to_vec(),
+            output_bits: Vec::new()
+        };
+        let result = predictor.result(&input);
+
+        // Mirror `Game::to_output_bits`'s own convention (keyed on winner_seed >= loser_seed)
+        // instead of assuming which index is `high`: they tie only when seeds are equal, the
+        // common case in the very matchups (two 1-seeds in the Final Four) that decide the
+        // headline odds
+        let (high_idx, low_idx) = if high.seed >= low.seed { (0, 1) } else { (1, 0) };
+        let margin = result[high_idx] as f64 - result[low_idx] as f64;
+        let high_win_prob = (1.0 / (1.0 + (-margin / MARGIN_SCALE).exp())).clamp(0.0, 1.0);
+        winners.push(if rng.gen_bool(high_win_prob) { high.clone() } else { low.clone() });
+    }
+
+    Ok(winners)
+}
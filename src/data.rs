@@ -15,7 +15,26 @@ pub const NUM_OUTPUTS: usize = 3 * 8;
 /// Maximum length for a team name
 pub const NAME_LEN: usize = 32;
 
+/// Convert an &str team name into the fixed-size char array `Game`/`SeedEntry` store it as,
+/// truncating anything past `NAME_LEN`
+pub fn name_to_chars(name: &str) -> [char; NAME_LEN] {
+    let mut list = ['\0'; NAME_LEN];
+    let mut i = 0;
+    for c in name.chars() {
+        list[i] = c;
+        i += 1;
+        if i >= NAME_LEN {
+            break;
+        }
+    }
+    list
+}
+
 /// What level of the tournament a game took place in
+// Variant names stay as-is (not Round-prefixed/suffixed per clippy's taste) so they keep
+// matching the CSV data file's round column verbatim (the CLI's round argument takes clap's
+// kebab-case form of each name)
+#[allow(clippy::enum_variant_names)]
 #[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
 pub enum Round {
     OpeningRound,
@@ -69,7 +88,7 @@ impl Region {
 }
 
 /// What a line of the CSV file looks like
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Clone, Copy, Debug)]
 pub struct Game {
     /// In the format 85, 86... 12, ... 18
     pub year: [char; 2],
@@ -121,11 +140,7 @@ impl Game {
         // Years are 1985 through 2018, 2018-1985=33, so we can store that in a single u8
         bits[0] = self.year.iter().collect::<String>().parse::<u8>()?;
         bits[1] = (self.round.to_u8() << 3)
-            + if self.region.is_some() {
-                self.region.unwrap().to_u8()
-            } else {
-                0
-            };
+            + self.region.map(|region| region.to_u8()).unwrap_or(0);
         if self.winner_seed >= self.loser_seed {
             bits[3] = ((self.winner_seed & 0x0F) << 4) + (self.loser_seed & 0x0F);
         } else {
@@ -158,3 +173,56 @@ impl Game {
     }
 }
 
+/// One team's row in a bracket seeding file (see `bracket::simulate`): regions list their 16
+/// teams in true bracket order, so pairing adjacent rows each round reproduces the real bracket
+/// without needing to re-derive standard NCAA seed pairings
+#[derive(Deserialize, Clone, Debug)]
+pub struct SeedEntry {
+    pub region: Region,
+    pub seed: u8,
+    pub name: String
+}
+
+impl SeedEntry {
+    /// Read in the columns of the seeding file
+    pub fn vec_from_file(fname: &str) -> Result<Vec<Self>, Box<dyn Error>> {
+        let mut data = Vec::new();
+
+        let mut reader = Reader::from_path(fname)?;
+        for result in reader.deserialize() {
+            data.push(result?);
+        }
+
+        Ok(data)
+    }
+}
+
+/// A single game already converted into the fixed-width byte arrays the network trains on
+#[derive(Clone, Debug)]
+pub struct RawGameInfo {
+    pub input_bits: Vec<u8>,
+    pub output_bits: Vec<u8>
+}
+
+/// The full training set, converted once up front so generations don't re-parse the CSV
+#[derive(Clone, Debug)]
+pub struct DataSet {
+    pub games: Vec<RawGameInfo>
+}
+
+impl DataSet {
+    /// Convert every `Game` row into its raw bit representation
+    pub fn from_games(games: Vec<Game>) -> Result<Self, Box<dyn Error>> {
+        let mut raw_games = Vec::new();
+        for game in games {
+            raw_games.push(RawGameInfo {
+                input_bits: game.to_input_bits()?.to_vec(),
+                output_bits: game.to_output_bits().to_vec()
+            });
+        }
+        Ok(Self {
+            games: raw_games
+        })
+    }
+}
+
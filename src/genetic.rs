@@ -3,43 +3,123 @@
  * Description: Helper functions for performing the genetic algorithm
  */
 
-use std::time::Instant;
-use futures::future::try_join_all;
-use tokio::{
-    spawn, task::JoinHandle
+use std::{
+    collections::{
+        HashMap, HashSet
+    }, time::Instant
 };
+use clap::ValueEnum;
+use rand::{
+    Rng, rngs::ThreadRng, thread_rng
+};
+use rayon::prelude::*;
 use crate::{
     data::{
         RawGameInfo, DataSet, NUM_OUTPUTS
-    }, network::Network
+    }, network::Network, neuron::Activation
 };
 
-const POP_SIZE: usize = 5000;
+const POP_SIZE: usize = 2000;
 
 /*
- * Generate starting batch
- * Non-parallel version tested and slower
+ * Scores are kept as a u64 "higher is better" fitness regardless of mode, so sorting/tournament
+ * selection don't need to know which mode produced them. Regression mode's raw error is
+ * subtracted from this ceiling to turn "lower error is better" into "higher fitness is better".
  */
-pub async fn gen_pop() -> Vec<Network> {
-    let mut pop_funcs = Vec::new();
-    for _ in 0..POP_SIZE {
-        pop_funcs.push(spawn(Network::new_random()));
+const REGRESSION_FITNESS_CEILING: u64 = 1_000_000;
+
+/// How a network's predicted output bytes are scored against the expected ones
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FitnessMode {
+    /// Count matching bits between predicted and expected bytes (the original behavior).
+    /// Not currently wired to a CLI flag - flip FITNESS_MODE in main.rs to use it
+    #[allow(dead_code)]
+    BitMatch,
+    /// Score numeric closeness of the predicted bytes instead of their bit pattern.
+    /// `squared` picks squared vs. absolute error, `winner_bonus` is added whenever the model
+    /// at least gets which of the two scores is higher correct, even if the exact values are off
+    Regression {
+        squared: bool,
+        winner_bonus: u64
+    }
+}
+
+/// Which parent-picking strategy `reproduce` uses to fill out the next generation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Selection {
+    /// Pair up adjacent individuals from the sorted top half of the population
+    TopHalf,
+    /// Sample `k` individuals uniformly at random and take the fittest, repeated per parent.
+    /// Weights selection toward fitness without requiring a parent be strictly top-ranked
+    Tournament {
+        k: usize
     }
-    try_join_all(pop_funcs).await.unwrap()
+}
+
+/// CLI-facing version of `Selection`, since `Tournament`'s `k` isn't itself a CLI-selectable
+/// value (it stays a constant) - converted to a `Selection` once parsed
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum SelectionKind {
+    TopHalf,
+    Tournament
+}
+
+/*
+ * Generate starting batch
+ * Each network is cheap CPU-only work, so a rayon thread pool amortizes across the whole
+ * population instead of paying a tokio task's scheduling overhead per individual
+ */
+pub fn gen_pop(activations: Vec<Activation>) -> Vec<Network> {
+    (0..POP_SIZE).into_par_iter()
+        .map(|_| Network::new_random(activations.clone()))
+        .collect()
 }
 
 /*
  * Test the population on the data and sort
- * Parallel is slower
+ * Returns each individual's fitness, in the same (now sorted) order as `pop`, so `reproduce`
+ * can weight selection by it instead of relying purely on sorted position
+ *
+ * Elitism and cloning mean many networks survive identical across generations, so `fit_cache`
+ * (keyed by `Network::content_hash`) is checked before running `test_all` for an individual and
+ * filled in on a miss. Pass `use_cache: false` to force every individual to be re-evaluated, e.g.
+ * for deterministic benchmarking. The rest run through rayon's thread pool: each network's
+ * fitness is pure CPU work with no I/O, so data-parallelism across the population scales across
+ * cores without a tokio task's per-item scheduling overhead.
  */
-pub async fn test_and_sort(pop: &mut Vec<Network>, data_set: DataSet) {
+pub fn test_and_sort(
+        pop: &mut Vec<Network>, data_set: DataSet, mode: FitnessMode,
+        fit_cache: &mut HashMap<u64, u64>, use_cache: bool) -> Vec<u64> {
     let now = Instant::now();
-    let handles: Vec<JoinHandle<u64>> = pop.iter().map(|pred| {
-        spawn(test_all(pred.clone(), data_set.clone()))
-    }).collect();
-    let results: Vec<u64> = try_join_all(handles).await.unwrap();
+
+    let hashes: Vec<u64> = pop.iter().map(|pred| pred.content_hash()).collect();
+    let hits = hashes.iter()
+        .filter(|hash| use_cache && fit_cache.contains_key(hash))
+        .count();
+
+    let results: Vec<u64> = pop.par_iter().zip(hashes.par_iter())
+        .map(|(pred, hash)| {
+            if use_cache {
+                if let Some(fit) = fit_cache.get(hash) {
+                    return *fit;
+                }
+            }
+            test_all(pred, &data_set, mode)
+        })
+        .collect();
+    for (hash, fit) in hashes.iter().zip(results.iter()) {
+        fit_cache.insert(*hash, *fit);
+    }
+    // Only this generation's hashes can ever hit again (elites/unmutated clones carry theirs
+    // into the next one); anything else is a prior generation's dead weight
+    let live_hashes: HashSet<u64> = hashes.into_iter().collect();
+    fit_cache.retain(|hash, _| live_hashes.contains(hash));
+
     let elapsed = now.elapsed();
-    println!("Test took {}s", elapsed.as_secs_f64());
+    let hit_rate = if use_cache && !pop.is_empty() { hits as f64 / pop.len() as f64 } else { 0.0 };
+    println!(
+        "Test took {}s (cache hit rate: {:.1}%)", elapsed.as_secs_f64(), hit_rate * 100.0
+    );
 
     // Attach results to the population and sort together
     let now = Instant::now();
@@ -53,69 +133,286 @@ pub async fn test_and_sort(pop: &mut Vec<Network>, data_set: DataSet) {
     for i in 0..pop_and_res.len() {
         pop[i] = pop_and_res[i].0.clone(); // Prefer to do an unzip and set, but not working
     }
+    let fitness: Vec<u64> = pop_and_res.iter().map(|(_, res)| **res).collect();
     let elapsed = now.elapsed();
     println!("Sort took {}s", elapsed.as_secs_f64());
 
-    let best = *pop_and_res[0].1;
-    let max = data_set.games.len() * NUM_OUTPUTS;
+    let best = fitness[0];
+    let max = fitness_ceiling(mode, &data_set);
     println!("Gen best: {} / {} = {}", best, max, (best as f64) / (max as f64));
+
+    fitness
 }
 
 /*
  * Load input and output data and test performance (# output bits right)
- * Faster to do sequential here ~5s
- * Slowest function
+ * Per-game work is tiny, so this stays sequential per network; `test_and_sort` is where the
+ * parallelism lives, spreading whole networks (not individual games) across rayon's pool
  */
-async fn test_all(pred: Network, data_set: DataSet) -> u64 {
-    let mut sum = 0;
-    for game in data_set.games.iter() {
-        sum += single_test(pred.clone(), &game).await;
-    }
-    sum
+fn test_all(pred: &Network, data_set: &DataSet, mode: FitnessMode) -> u64 {
+    data_set.games.iter().map(|game| single_test(pred, game, mode)).sum()
 }
 
 // The fitness function
-async fn single_test(pred: Network, game: &RawGameInfo) -> u64 {
-    let output_bits = game.output_bits.clone();
-    let res = pred.result(game).await;
-    res.iter().zip(output_bits.iter()).map(|(res_bit, expected)| {
-        let mut bits_correct = 0;
-        for i in 0..8 {
-            if ((res_bit >> i) & 0x01) == ((expected >> i) & 0x01) {
-                bits_correct += 1;
+fn single_test(pred: &Network, game: &RawGameInfo, mode: FitnessMode) -> u64 {
+    let res = pred.result(game);
+    match mode {
+        FitnessMode::BitMatch => res.iter().zip(game.output_bits.iter()).map(|(res_byte, expected)| {
+            let mut bits_correct = 0;
+            for i in 0..8 {
+                if ((res_byte >> i) & 0x01) == ((expected >> i) & 0x01) {
+                    bits_correct += 1;
+                }
             }
+            bits_correct
+        }).sum(),
+        FitnessMode::Regression { squared, winner_bonus } => {
+            let mut error: u64 = 0;
+            for (res_byte, expected) in res.iter().zip(game.output_bits.iter()) {
+                let diff = (*res_byte as i64 - *expected as i64).unsigned_abs();
+                error += if squared { diff * diff } else { diff };
+            }
+
+            let mut fitness = REGRESSION_FITNESS_CEILING.saturating_sub(error);
+            if winner_bonus > 0 && res.len() >= 2 && game.output_bits.len() >= 2 {
+                let predicted_winner_scored_more = res[0] >= res[1];
+                let actual_winner_scored_more = game.output_bits[0] >= game.output_bits[1];
+                if predicted_winner_scored_more == actual_winner_scored_more {
+                    fitness += winner_bonus;
+                }
+            }
+            fitness
         }
-        bits_correct
-    }).sum()
+    }
 }
 
-// Take the top half of population and reproduce to make a better population (expects sorted)
-pub async fn reproduce(pop: &mut Vec<Network>) {
+/*
+ * Memetic/Lamarckian step: polish the top `top_n` networks in `pop` (expects `pop` sorted
+ * best-first, as `test_and_sort` leaves them) with a few backpropagation iterations against
+ * `data_set` before they reproduce, so fine local improvement doesn't have to wait on mutation
+ * to stumble onto it. `steps == 0` is a no-op, so the feature stays off by default.
+ */
+pub fn memetic_refine(pop: &mut [Network], data_set: &DataSet, top_n: usize, lr: f64, steps: usize) {
+    if steps == 0 {
+        return;
+    }
     let now = Instant::now();
-    for i in 0..POP_SIZE / 2 {
-        if i % 2 == 0 { // We're doing every two parents in the first half
-            let mut child_a = pop[i].clone();
-            let mut child_b = pop[i + 1].clone();
-
-            // Make a copy of parents at bottom of vector
-            pop.push(child_a.clone());
-            pop.push(child_b.clone());
-
-            // Then trade and mutate to modify children from parents
-            child_a.random_trade(&mut child_b).await;
-            child_a.mutate().await;
-            child_b.mutate().await;
-
-            // Add the children
-            pop.push(child_a);
-            pop.push(child_b);
-        }
+    let top_n = top_n.min(pop.len());
+    for net in pop.iter_mut().take(top_n) {
+        net.refine(&data_set.games, lr, steps);
     }
+    let elapsed = now.elapsed();
+    println!("Memetic refinement took {}s", elapsed.as_secs_f64());
+}
 
-    // Remove the "bad" individuals and og parents (now at the top since we copied good to bottom)
-    for _ in 0..POP_SIZE {
-        pop.remove(0);
+/*
+ * Build the next generation from `pop` (expects `pop`/`fitness` sorted best-first, as
+ * `test_and_sort` leaves them).
+ *
+ * - The top `elite_count` networks survive unchanged (elitism), so the best solution found so
+ *   far never regresses.
+ * - Everyone else is filled in by picking two parents per `selection`, cloning them, and - only
+ *   with probability `crossover_chance` - trading traits before mutating. With probability
+ *   `1.0 - crossover_chance` a pair is left untraded, so good genomes can still pass through
+ *   unmixed, just mutated.
+ */
+pub fn reproduce(
+        pop: &mut Vec<Network>, fitness: &[u64],
+        selection: Selection, elite_count: usize, crossover_chance: f64, mutation_boost: f64) {
+    let now = Instant::now();
+    let pop_size = pop.len();
+
+    let mut next_gen = Vec::with_capacity(pop_size);
+    next_gen.extend(pop.iter().take(elite_count.min(pop_size)).cloned());
+
+    let mut rng = thread_rng();
+    while next_gen.len() < pop_size {
+        let (mut child_a, mut child_b) = select_parents(pop, fitness, selection, &mut rng);
+
+        if rng.gen_bool(crossover_chance) {
+            child_a.random_trade(&mut child_b);
+        }
+        child_a.mutate(mutation_boost);
+        child_b.mutate(mutation_boost);
+
+        next_gen.push(child_a);
+        if next_gen.len() < pop_size {
+            next_gen.push(child_b);
+        }
     }
+
+    *pop = next_gen;
     let elapsed = now.elapsed();
     println!("Reproduction took {}s", elapsed.as_secs_f64());
 }
+
+// Pick a pair of parents from `pop` (expected sorted best-first) per the given `selection`
+fn select_parents(
+        pop: &[Network], fitness: &[u64],
+        selection: Selection, rng: &mut ThreadRng) -> (Network, Network) {
+    match selection {
+        Selection::Tournament { k } => (
+            tournament_select(pop, fitness, k, rng).clone(),
+            tournament_select(pop, fitness, k, rng).clone()
+        ),
+        Selection::TopHalf => {
+            let half = (pop.len() / 2).max(1);
+            let pair = rng.gen_range(0..(half / 2).max(1));
+            let i = 2 * pair;
+            let j = (i + 1).min(pop.len() - 1);
+            (pop[i].clone(), pop[j].clone())
+        }
+    }
+}
+
+// Sample k individuals uniformly at random and return the fittest of them
+fn tournament_select<'a>(
+        pop: &'a [Network], fitness: &[u64], k: usize, rng: &mut ThreadRng) -> &'a Network {
+    let mut best = rng.gen_range(0..pop.len());
+    for _ in 1..k {
+        let challenger = rng.gen_range(0..pop.len());
+        if fitness[challenger] > fitness[best] {
+            best = challenger;
+        }
+    }
+    &pop[best]
+}
+
+/*
+ * Drives a criterion-based training loop instead of a fixed generation count: tracks best
+ * fitness across generations, boosts mutation when progress stalls on a plateau, and signals
+ * the caller to stop once the plateau has run past `patience` generations.
+ */
+pub struct TrainingController {
+    best_history: Vec<u64>,
+    window: usize,
+    plateau_eps: f64,
+    patience: usize,
+    mutation_boost_factor: f64,
+    plateau_gens: usize,
+    boost: f64,
+    fitness_ceiling: u64,
+    target_fraction: f64
+}
+
+impl TrainingController {
+    pub fn new(
+            window: usize, plateau_eps: f64, patience: usize, mutation_boost_factor: f64,
+            fitness_ceiling: u64, target_fraction: f64) -> Self {
+        Self {
+            best_history: Vec::new(),
+            window, plateau_eps, patience, mutation_boost_factor,
+            plateau_gens: 0,
+            boost: 1.0,
+            fitness_ceiling, target_fraction
+        }
+    }
+
+    /*
+     * Record this generation's best fitness and update the mutation boost. Returns true once
+     * either progress has been flat for `patience` generations in a row, or `best` has reached
+     * `target_fraction` of `fitness_ceiling`, meaning training should stop.
+     */
+    pub fn record(&mut self, best: u64) -> bool {
+        self.best_history.push(best);
+
+        if self.fitness_ceiling > 0
+                && (best as f64 / self.fitness_ceiling as f64) >= self.target_fraction {
+            return true;
+        }
+
+        if self.best_history.len() > self.window {
+            let past = self.best_history[self.best_history.len() - self.window - 1] as f64;
+            let slope = (best as f64 - past) / self.window as f64;
+
+            if slope.abs() < self.plateau_eps {
+                self.plateau_gens += 1;
+                self.boost *= self.mutation_boost_factor;
+            } else {
+                self.plateau_gens = 0;
+                self.boost = 1.0;
+            }
+        }
+
+        self.plateau_gens >= self.patience
+    }
+
+    // Current multiplier to scale WEIGHT_MUTATE_CHANCE/AMOUNT and friends by
+    pub fn mutation_boost(&self) -> f64 {
+        self.boost
+    }
+
+    // Tab-separated progress line (generation, best, average, std of this generation's fitness
+    // values) so a training run's progress can be plotted
+    pub fn progress_log(gen: usize, fitness: &[u64]) -> String {
+        let n = fitness.len().max(1) as f64;
+        let avg = fitness.iter().map(|f| *f as f64).sum::<f64>() / n;
+        let variance = fitness.iter().map(|f| (*f as f64 - avg).powi(2)).sum::<f64>() / n;
+        format!("{}\t{}\t{:.2}\t{:.2}", gen, fitness[0], avg, variance.sqrt())
+    }
+}
+
+/// Highest total fitness attainable under `mode` against `data_set`, used to turn a raw best
+/// fitness into "fraction of the way to a perfect score" for `TrainingController`'s target
+/// criterion
+pub fn fitness_ceiling(mode: FitnessMode, data_set: &DataSet) -> u64 {
+    let games = data_set.games.len() as u64;
+    match mode {
+        FitnessMode::BitMatch => games * NUM_OUTPUTS as u64,
+        FitnessMode::Regression { winner_bonus, .. } => games * (REGRESSION_FITNESS_CEILING + winner_bonus)
+    }
+}
+
+/*
+ * Train for a wall-clock time budget instead of a fixed/criterion-driven generation count,
+ * using a simulated-annealing-style schedule: mutation starts large and shrinks as the
+ * deadline approaches, and the current best is occasionally allowed to accept a worse mutated
+ * variant early on to keep exploring. Returns the best network seen across the whole run.
+ */
+#[allow(clippy::too_many_arguments)]
+pub fn train_timed(
+        deadline_secs: f64, mut pop: Vec<Network>, data_set: DataSet,
+        selection: Selection, elite_count: usize, crossover_chance: f64, anneal_scale: f64,
+        mode: FitnessMode, use_cache: bool) -> Network {
+    let start = Instant::now();
+    let mut rng = thread_rng();
+    let mut best = pop[0].clone();
+    let mut best_fit: u64 = 0;
+    let mut fit_cache = HashMap::new();
+
+    loop {
+        let elapsed = start.elapsed().as_secs_f64();
+        if elapsed >= deadline_secs {
+            break;
+        }
+        // "Temperature": 1.0 at the start of the run, cooling to 0 at the deadline
+        let t = (1.0 - elapsed / deadline_secs).max(0.0001);
+
+        let fitness = test_and_sort(
+            &mut pop, data_set.clone(), mode, &mut fit_cache, use_cache
+        );
+        if fitness[0] > best_fit {
+            best_fit = fitness[0];
+            best = pop[0].clone();
+        }
+
+        // Mutate a copy of the current best and accept it even if it's slightly worse with
+        // probability exp((new_fit - old_fit) / (t * anneal_scale)), like a cooling schedule
+        let mut candidate = pop[0].clone();
+        candidate.mutate(t);
+        let candidate_fit = test_all(&candidate, &data_set, mode);
+        let delta = candidate_fit as f64 - fitness[0] as f64;
+        let accept = delta >= 0.0 || rng.gen_bool((delta / (t * anneal_scale)).exp().min(1.0));
+        if accept {
+            if candidate_fit > best_fit {
+                best_fit = candidate_fit;
+                best = candidate.clone();
+            }
+            pop[0] = candidate;
+        }
+
+        reproduce(&mut pop, &fitness, selection, elite_count, crossover_chance, t);
+    }
+
+    best
+}
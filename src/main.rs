@@ -5,46 +5,66 @@
 
 mod data;
 mod args;
+mod neuron;
+mod network;
+mod genetic;
+mod bracket;
 
 use std::{
+    collections::HashMap,
     time::Instant,
     error::Error
 };
 use clap::Parser;
-use scratch_genetic::genetic::{
-    gen_pop, test_and_sort, reproduce, load_and_predict, export_model
-};
 use crate::{
     args::{
         CliArgs, PredictorCommands
     }, data::{
-        Game, NUM_INPUTS, NUM_OUTPUTS, Round, Region, NAME_LEN
-    }
+        name_to_chars, DataSet, Game, RawGameInfo, Round, Region, NAME_LEN
+    }, genetic::{
+        gen_pop, test_and_sort, reproduce, train_timed, memetic_refine,
+        fitness_ceiling, FitnessMode, Selection, SelectionKind, TrainingController
+    }, network::Network,
+    neuron::Activation
 };
 
-// Neuron connection settings
-pub const NEURON_ACTIVATION_THRESH: f64 = 0.60;
-pub const TRAIT_SWAP_CHANCE: f64 = 0.80;
-pub const WEIGHT_MUTATE_CHANCE: f64 = 0.65;
-pub const WEIGHT_MUTATE_AMOUNT: f64 = 0.5;
-pub const OFFSET_MUTATE_CHANCE: f64 = 0.25;
-pub const OFFSET_MUTATE_AMOUNT: f64 = 0.05;
-
-// Neural network settings
-pub const LAYER_SIZES: [usize; 4] = [ 16, 32, 32, 2 ];
-
-// Algortithm settings
-const POP_SIZE: usize = 2000;
-
-const DATA_FILE_NAME: &'static str = "march_madness_historical_data.csv";
-const MODEL_FILE_NAME: &'static str = "model.mmp";
-const NUM_GENS: usize = 1000;
+const DATA_FILE_NAME: &str = "march_madness_historical_data.csv";
+const MODEL_FILE_NAME: &str = "model.mmp";
+
+// Selection settings for genetic::reproduce. Tournament size only applies under
+// SelectionKind::Tournament; TopHalf ignores it
+const TOURNAMENT_K: usize = 5;
+const ELITE_COUNT: usize = 50;
+const CROSSOVER_CHANCE: f64 = 0.80;
+
+// Stop criteria / plateau handling for genetic::TrainingController
+const MAX_GENS: usize = 1000;
+const PLATEAU_WINDOW: usize = 10;
+const PLATEAU_EPS: f64 = 0.5;
+const PATIENCE: usize = 20;
+const MUTATION_BOOST_FACTOR: f64 = 1.5;
+// Stop early if the best network reaches this fraction of a perfect score
+const TARGET_FITNESS_FRACTION: f64 = 0.98;
+
+// How many of the top networks get polished by genetic::memetic_refine each generation
+const REFINE_TOP_N: usize = 5;
+
+// Annealing schedule scale for genetic::train_timed
+const ANNEAL_SCALE: f64 = 50.0;
+
+// How fitness is scored: closeness of the predicted score, not just matching bit patterns,
+// with a bonus for at least picking the right winner
+const FITNESS_MODE: FitnessMode = FitnessMode::Regression {
+    squared: true,
+    winner_bonus: 10_000
+};
 
 // Entry point
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     match CliArgs::parse().command {
-        PredictorCommands::Train => train().await,
+        PredictorCommands::Train { timed_secs, no_cache, selection, refine_lr, refine_steps } =>
+            train(timed_secs, !no_cache, selection, refine_lr, refine_steps).await,
         PredictorCommands::Predict {
             year, round, region,
             high_seed, high_seed_team,
@@ -53,43 +73,77 @@ async fn main() -> Result<(), Box<dyn Error>> {
             year.as_str(), round, region,
             high_seed, high_seed_team.as_str(),
             low_seed, low_seed_team.as_str()
-        ).await
+        ).await,
+        PredictorCommands::Bracket { seed_file, trials } =>
+            bracket::simulate(MODEL_FILE_NAME, seed_file.as_str(), trials).await
     }
 }
 
 // Train on march madness legacy data
-pub async fn train() -> Result<(), Box<dyn Error>> {
+pub async fn train(
+        timed_secs: Option<f64>, use_cache: bool, selection: SelectionKind,
+        refine_lr: f64, refine_steps: usize) -> Result<(), Box<dyn Error>> {
     println!("Training new March Madness Predictor Model");
 
+    let selection = match selection {
+        SelectionKind::TopHalf => Selection::TopHalf,
+        SelectionKind::Tournament => Selection::Tournament { k: TOURNAMENT_K }
+    };
+
     println!("Loading training data from {}", DATA_FILE_NAME);
     let games = Game::vec_from_file(DATA_FILE_NAME)?;
-    let games: Vec<(Vec<u8>, Vec<u8>)> = games.iter().map(|game| {( // Redefines games
-        game.clone().to_input_bits().expect("Failed to convert to bits.").to_vec(),
-        game.clone().to_output_bits().to_vec()
-    )}).collect();
+    let data_set = DataSet::from_games(games)?;
 
     println!("Generating randomized population");
     let now = Instant::now();
-    let mut pop = gen_pop(
-        POP_SIZE,
-        LAYER_SIZES.to_vec(), NUM_INPUTS, NUM_OUTPUTS,
-        NEURON_ACTIVATION_THRESH, TRAIT_SWAP_CHANCE,
-        WEIGHT_MUTATE_CHANCE, WEIGHT_MUTATE_AMOUNT,
-        OFFSET_MUTATE_CHANCE, OFFSET_MUTATE_AMOUNT
-    ).await;
+    // ReLU hidden layers, Sigmoid output: smooth gradients without a dead flat cutoff
+    let layer_activations = vec![
+        Activation::ReLU, Activation::ReLU, Activation::ReLU, Activation::ReLU, Activation::Sigmoid
+    ];
+    let pop = gen_pop(layer_activations);
     let elapsed = now.elapsed();
     println!("Generation took {}s", elapsed.as_secs_f64());
 
-    println!("Starting training");
-    for i in 0..NUM_GENS {
-        println!("Generation {} / {}", i, NUM_GENS);
-        test_and_sort(&mut pop, &games).await;
-        reproduce(&mut pop).await;
-    }
+    let best = if let Some(deadline_secs) = timed_secs {
+        println!("Training for {}s with an annealed mutation schedule", deadline_secs);
+        train_timed(
+            deadline_secs, pop, data_set,
+            selection, ELITE_COUNT, CROSSOVER_CHANCE, ANNEAL_SCALE, FITNESS_MODE, use_cache
+        )
+    } else {
+        println!("Starting training");
+        let mut pop = pop;
+        let mut controller = TrainingController::new(
+            PLATEAU_WINDOW, PLATEAU_EPS, PATIENCE, MUTATION_BOOST_FACTOR,
+            fitness_ceiling(FITNESS_MODE, &data_set), TARGET_FITNESS_FRACTION
+        );
+        let mut fit_cache = HashMap::new();
+        println!("gen\tbest\tavg\tstd");
+        for i in 0..MAX_GENS {
+            println!("Generation {} / {}", i, MAX_GENS);
+            let fitness = test_and_sort(
+                &mut pop, data_set.clone(), FITNESS_MODE, &mut fit_cache, use_cache
+            );
+            println!("{}", TrainingController::progress_log(i, &fitness));
+            let stop_early = controller.record(fitness[0]);
+            memetic_refine(&mut pop, &data_set, REFINE_TOP_N, refine_lr, refine_steps);
+            reproduce(
+                &mut pop, &fitness,
+                selection, ELITE_COUNT, CROSSOVER_CHANCE, controller.mutation_boost()
+            );
+            if stop_early {
+                println!(
+                    "Fitness plateaued or hit the target score, stopping early at generation {}", i
+                );
+                break;
+            }
+        }
+        pop[0].clone()
+    };
 
     // Save algorithm
     println!("Saving model to {}", MODEL_FILE_NAME);
-    export_model(MODEL_FILE_NAME, &pop[0]).await;
+    best.save_model(MODEL_FILE_NAME);
 
     Ok(())
 }
@@ -127,7 +181,12 @@ pub async fn predict(
     };
 
     println!("Predicting!");
-    let result = load_and_predict(MODEL_FILE_NAME, &game.to_input_bits()?.to_vec()).await;
+    let predictor = Network::from_file(MODEL_FILE_NAME)?;
+    let input_bits = RawGameInfo {
+        input_bits: game.to_input_bits()?.to_vec(),
+        output_bits: Vec::new()
+    };
+    let result = predictor.result(&input_bits);
 
     println!("Predicted score for {}: {}", high_seed_team, result[0]);
     println!("Predicted score for {}: {}", low_seed_team, result[1]);
@@ -136,17 +195,3 @@ pub async fn predict(
     Ok(())
 }
 
-/// Convert an &str team name into a char array of fixed size
-fn name_to_chars(name: &str) -> [char; NAME_LEN] {
-    let mut list = ['\0'; NAME_LEN];
-    let mut i = 0;
-    for c in name.chars() {
-        list[i] = c;
-        i += 1;
-        if i >= NAME_LEN {
-            break;
-        }
-    }
-    list
-}
-
@@ -4,163 +4,305 @@
  */
 
 use std::{
+    collections::hash_map::DefaultHasher,
+    error::Error,
     fs::{
         File, remove_file
+    }, hash::{
+        Hash, Hasher
     }, io::{
         Write, Read
-    }, sync::Arc,
+    },
     path::Path
 };
-use futures::future::try_join_all;
-use tokio::{
-    spawn,
-    sync::Mutex,
-    task::JoinHandle
+use rand::{
+    Rng, thread_rng
 };
 use crate::{
     data::{
         RawGameInfo, NUM_INPUTS, NUM_OUTPUTS
     }, neuron::{
-        random_gen_neurons, activations, neurons_trade, neurons_mutate
+        mutate_delta, Activation, TRAIT_SWAP_CHANCE,
+        MUTATION_KIND, WEIGHT_MUTATE_CHANCE, WEIGHT_MUTATE_AMOUNT, WEIGHT_MUTATE_SIGMA,
+        OFFSET_MUTATE_CHANCE, OFFSET_MUTATE_AMOUNT, OFFSET_MUTATE_SIGMA,
+        WEIGHT_RANGE, OFFSET_RANGE
     }
 };
 
 pub const NUM_LAYERS: usize = 4;
 pub const LAYER_SIZES: [usize; NUM_LAYERS] = [ 8, 32, 32, 16 ];
 
-#[derive(Debug)]
+/// Tags a model file as belonging to this project, so loading garbage fails fast and loudly
+const MODEL_MAGIC: &[u8; 4] = b"MMP1";
+/// Bumped whenever the on-disk layout below changes
+const MODEL_VERSION: u8 = 1;
+
+#[derive(Debug, Clone)]
 pub struct Network {
-    pub layer_conn_set: Vec<Vec<Arc<Mutex<Vec<(f64, f64)>>>>>
+    pub layer_conn_set: Vec<Vec<Vec<(f64, f64)>>>,
+
+    /// Activation function used by each layer, input layer first, output layer last
+    pub activations: Vec<Activation>
 }
 
 impl Network {
-    /*
-     * Generate all connections randomly
-     * Unlike underlying functions these ARE faster when multithreaded
-     */
-    pub async fn new_random() -> Arc<Mutex<Self>> {
-        let mut handles: Vec<JoinHandle<Vec<Arc<Mutex<Vec<(f64, f64)>>>>>> = Vec::new();
+    // Generate all connections randomly. Plain sequential work: rayon parallelizes across the
+    // population in genetic::gen_pop instead of across a single network's tiny connection count
+    pub fn new_random(activations: Vec<Activation>) -> Self {
+        assert_eq!(
+            activations.len(), NUM_LAYERS + 1,
+            "Need one Activation per layer, including the output layer"
+        );
+
+        let mut layer_conn_set = Vec::with_capacity(NUM_LAYERS + 1);
         for i in 0..=NUM_LAYERS {
-            handles.push(spawn(match i {
+            layer_conn_set.push(match i {
                 0 => random_gen_neurons(LAYER_SIZES[i], NUM_INPUTS),
-                NUM_LAYERS => random_gen_neurons(NUM_OUTPUTS, LAYER_SIZES[i - 1]),
+                // One output neuron per predicted score (NUM_OUTPUTS counts bits for the
+                // byte-level fitness/IO math elsewhere, not neurons)
+                NUM_LAYERS => random_gen_neurons(NUM_OUTPUTS / 8, LAYER_SIZES[i - 1]),
                 _ => random_gen_neurons(LAYER_SIZES[i], LAYER_SIZES[i - 1])
-            }));
+            });
         }
-        let layer_conn_set = try_join_all(handles).await.unwrap();
 
-        Arc::new(Mutex::new(Self {
-            layer_conn_set
-        }))
+        Self {
+            layer_conn_set,
+            activations
+        }
     }
 
-    // Cannot be parallelized
-    pub async fn result(&self, game: Arc<RawGameInfo>) -> Vec<u8> {
-        let mut last_bits = game;
-        for layer_conn in self.layer_conn_set.iter() {
-            let layer = activations(layer_conn, last_bits).await;
-            last_bits = Arc::new(RawGameInfo {
-                input_bits: layer,
-                output_bits: Vec::new()
-            });
+    /*
+     * Only the raw game input is bit-packed; every layer after that carries real-valued
+     * activations between neurons instead of bits or quantized bytes, so gradient/magnitude
+     * information survives between layers. The final layer's activations are quantized down to
+     * bytes only once, at the very end, to land in the same range as `output_bits`
+     */
+    pub fn result(&self, game: &RawGameInfo) -> Vec<u8> {
+        let mut values = unpack_input_bits(&game.input_bits);
+        for (layer_conn, activation) in self.layer_conn_set.iter().zip(self.activations.iter()) {
+            values = layer_forward(layer_conn, &values, *activation);
+        }
+
+        let out_activation = self.activations.last().copied().unwrap_or(Activation::Identity);
+        values.iter().map(|value| out_activation.quantize(*value)).collect()
+    }
+
+    // Forward pass that also caches each layer's pre-activation sum and activated output,
+    // needed by `refine`'s backprop step
+    fn forward_cached(&self, input_bits: &[u8]) -> (Vec<Vec<f64>>, Vec<Vec<f64>>) {
+        let mut sums_per_layer = Vec::with_capacity(self.layer_conn_set.len());
+        let mut outputs_per_layer = Vec::with_capacity(self.layer_conn_set.len());
+
+        let mut values = unpack_input_bits(input_bits);
+        for (layer_conn, activation) in self.layer_conn_set.iter().zip(self.activations.iter()) {
+            let mut sums = Vec::with_capacity(layer_conn.len());
+            let mut outputs = Vec::with_capacity(layer_conn.len());
+            for conns in layer_conn.iter() {
+                let mut sum = 0.0;
+                for (i, (weight, offset)) in conns.iter().enumerate() {
+                    sum += weight * values[i] + offset;
+                }
+                outputs.push(activation.apply(sum));
+                sums.push(sum);
+            }
+            values = outputs.clone();
+            sums_per_layer.push(sums);
+            outputs_per_layer.push(outputs);
+        }
+
+        (sums_per_layer, outputs_per_layer)
+    }
+
+    /*
+     * Lamarckian/memetic local refinement: run `steps` full-batch backpropagation passes over
+     * `games` against squared error, nudging this network's weights directly towards a better
+     * fit before it reproduces, since a pure genetic search converges slowly near the optimum.
+     * Output-layer deltas are `(output - target) * f'(sum)`; hidden-layer deltas are
+     * `(sum of downstream_weight * downstream_delta) * f'(sum)`; each weight/offset is then
+     * nudged by `-lr * delta * input` / `-lr * delta`. The refined weights are written back
+     * into this genome so they carry forward into the next generation.
+     */
+    pub fn refine(&mut self, games: &[RawGameInfo], lr: f64, steps: usize) {
+        let num_layers = self.layer_conn_set.len();
+        if num_layers == 0 {
+            return;
+        }
+        let out_layer = num_layers - 1;
+
+        for _ in 0..steps {
+            for game in games {
+                let (_, outputs) = self.forward_cached(&game.input_bits);
+                let input_values = unpack_input_bits(&game.input_bits);
+
+                let mut deltas: Vec<Vec<f64>> = vec![Vec::new(); num_layers];
+
+                let out_activation = self.activations[out_layer];
+                deltas[out_layer] = outputs[out_layer].iter().enumerate().map(|(i, output)| {
+                    let target = out_activation.normalize_target(
+                        *game.output_bits.get(i).unwrap_or(&0)
+                    );
+                    (output - target) * out_activation.derivative(*output)
+                }).collect();
+
+                for l in (0..out_layer).rev() {
+                    let activation = self.activations[l];
+                    let mut layer_deltas = Vec::with_capacity(outputs[l].len());
+                    for n in 0..outputs[l].len() {
+                        let mut downstream_sum = 0.0;
+                        for (dn, downstream_neuron) in self.layer_conn_set[l + 1].iter().enumerate() {
+                            let weight = downstream_neuron[n].0;
+                            downstream_sum += weight * deltas[l + 1][dn];
+                        }
+                        layer_deltas.push(downstream_sum * activation.derivative(outputs[l][n]));
+                    }
+                    deltas[l] = layer_deltas;
+                }
+
+                for l in 0..num_layers {
+                    let layer_input = if l == 0 { &input_values } else { &outputs[l - 1] };
+                    for (n, conns) in self.layer_conn_set[l].iter_mut().enumerate() {
+                        for (i, (weight, offset)) in conns.iter_mut().enumerate() {
+                            *weight = (*weight - lr * deltas[l][n] * layer_input[i])
+                                .clamp(WEIGHT_RANGE.0, WEIGHT_RANGE.1);
+                            *offset = (*offset - lr * deltas[l][n])
+                                .clamp(OFFSET_RANGE.0, OFFSET_RANGE.1);
+                        }
+                    }
+                }
+            }
         }
-        last_bits.input_bits.clone()
     }
 
     // Can't be parallelized bc mutation
-    pub async fn random_trade(&mut self, other: &mut Self) {
+    pub fn random_trade(&mut self, other: &mut Self) {
         for i in 0..self.layer_conn_set.len() {
-            neurons_trade(&mut self.layer_conn_set[i], &mut other.layer_conn_set[i]).await;
+            neurons_trade(&mut self.layer_conn_set[i], &mut other.layer_conn_set[i]);
         }
     }
 
     // Can't be parallelized bc mutation
-    pub async fn mutate(&mut self) {
+    // `boost` scales the mutation chance/amount up to escape a plateau (1.0 = normal rate)
+    pub fn mutate(&mut self, boost: f64) {
         for layer_conn in self.layer_conn_set.iter_mut() {
-            neurons_mutate(layer_conn).await;
+            neurons_mutate(layer_conn, boost);
         }
     }
 
-    // Don't care to optimize. Performance doesn't really matter
-    pub fn from_file(fname: &str) -> Self {
-        let mut big_arr_size = NUM_INPUTS * LAYER_SIZES[0];
-        for i in 0..NUM_LAYERS - 1 {
-            big_arr_size += LAYER_SIZES[i] * LAYER_SIZES[i + 1];
-        }
-        big_arr_size += LAYER_SIZES[NUM_LAYERS - 1] * NUM_OUTPUTS;
-        big_arr_size *= 16; // 8 bytes for weight and 8 for offset
-        let mut big_arr = vec![0; big_arr_size];
-
-        let mut file = File::open(fname).expect("Failed to open model file!");
-        file.read_exact(&mut big_arr).expect("Failed to save model to file!");
-
-        let mut x = 0;
-        let mut layer_conn_set = Vec::new();
-        for i in 0 as usize..=NUM_LAYERS {
-            let in_layer_size = if i == 0 {
-                NUM_INPUTS
-            } else {
-                LAYER_SIZES[i - 1]
-            };
-            let out_layer_size = if i == NUM_LAYERS {
-                NUM_OUTPUTS
-            } else {
-                LAYER_SIZES[i]
-            };
+    /*
+     * Load a model written by `save_model`: a magic tag + version byte, then each layer's
+     * input/output size and activation, then the weight/offset pairs themselves. Topology is
+     * read from the file instead of assumed from NUM_LAYERS/LAYER_SIZES, so a model saved under
+     * a different build's topology loads correctly (or fails loudly) instead of silently
+     * reading garbage.
+     */
+    pub fn from_file(fname: &str) -> Result<Self, Box<dyn Error>> {
+        let mut file = File::open(fname)?;
+
+        let mut magic = [0; 4];
+        file.read_exact(&mut magic)?;
+        if &magic != MODEL_MAGIC {
+            return Err(format!("{} is not a march-madness-predictor model file", fname).into());
+        }
+
+        let mut version = [0; 1];
+        file.read_exact(&mut version)?;
+        if version[0] != MODEL_VERSION {
+            return Err(format!(
+                "Model file version {} isn't supported (expected {})", version[0], MODEL_VERSION
+            ).into());
+        }
 
-            let mut layer_conn = Vec::new();
+        let mut num_layers_buf = [0; 4];
+        file.read_exact(&mut num_layers_buf)?;
+        let num_layers = u32::from_be_bytes(num_layers_buf) as usize;
+
+        let mut layer_shapes = Vec::with_capacity(num_layers);
+        for _ in 0..num_layers {
+            let mut in_size_buf = [0; 4];
+            file.read_exact(&mut in_size_buf)?;
+            let mut out_size_buf = [0; 4];
+            file.read_exact(&mut out_size_buf)?;
+            let mut activation_buf = [0; 1];
+            file.read_exact(&mut activation_buf)?;
+            let activation = Activation::from_byte(activation_buf[0])
+                .ok_or_else(|| format!("Unknown activation tag {}", activation_buf[0]))?;
+
+            layer_shapes.push((
+                u32::from_be_bytes(in_size_buf) as usize,
+                u32::from_be_bytes(out_size_buf) as usize,
+                activation
+            ));
+        }
+
+        let mut layer_conn_set = Vec::with_capacity(num_layers);
+        let mut activations = Vec::with_capacity(num_layers);
+        for (in_layer_size, out_layer_size, activation) in layer_shapes {
+            let mut layer_conn = Vec::with_capacity(out_layer_size);
             for _ in 0..out_layer_size {
-                let mut neurons = Vec::new();
+                let mut neurons = Vec::with_capacity(in_layer_size);
                 for _ in 0..in_layer_size {
                     let mut weight_data = [0; 8];
-                    for k in 0..8 {
-                        weight_data[k] = big_arr[x];
-                        x += 1;
-                    }
+                    file.read_exact(&mut weight_data)?;
                     let mut offset_data = [0; 8];
-                    for k in 0..8 {
-                        offset_data[k] = big_arr[x];
-                        x += 1;
-                    }
+                    file.read_exact(&mut offset_data)?;
                     neurons.push(
                         (f64::from_be_bytes(weight_data), f64::from_be_bytes(offset_data))
                     );
                 }
-                layer_conn.push(Arc::new(Mutex::new(neurons)));
+                layer_conn.push(neurons);
             }
 
             layer_conn_set.push(layer_conn);
+            activations.push(activation);
         }
 
-        Self {
-            layer_conn_set
+        Ok(Self {
+            layer_conn_set,
+            activations
+        })
+    }
+
+    /*
+     * Hash this network's full weight/offset bytes (the same content `save_model` would write),
+     * so a fitness cache keyed on this can recognize networks that survived a generation
+     * unchanged (elites, unmutated clones) without re-running them against the whole data set
+     */
+    pub fn content_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        for (layer_conn, activation) in self.layer_conn_set.iter().zip(self.activations.iter()) {
+            activation.to_byte().hash(&mut hasher);
+            for conns in layer_conn {
+                for (weight, offset) in conns.iter() {
+                    weight.to_be_bytes().hash(&mut hasher);
+                    offset.to_be_bytes().hash(&mut hasher);
+                }
+            }
         }
+        hasher.finish()
     }
 
     // Don't care to optimize. Performance doesn't really matter
-    pub async fn save_model(&self, fname: &str) {
-        let mut big_arr_size = NUM_INPUTS * LAYER_SIZES[0];
-        for i in 0..NUM_LAYERS - 1 {
-            big_arr_size += LAYER_SIZES[i] * LAYER_SIZES[i + 1];
+    pub fn save_model(&self, fname: &str) {
+        let mut big_arr = Vec::new();
+        big_arr.extend_from_slice(MODEL_MAGIC);
+        big_arr.push(MODEL_VERSION);
+        big_arr.extend_from_slice(&(self.layer_conn_set.len() as u32).to_be_bytes());
+
+        for (layer_conn, activation) in self.layer_conn_set.iter().zip(self.activations.iter()) {
+            let in_size = match layer_conn.first() {
+                Some(conns) => conns.len(),
+                None => 0
+            };
+            big_arr.extend_from_slice(&(in_size as u32).to_be_bytes());
+            big_arr.extend_from_slice(&(layer_conn.len() as u32).to_be_bytes());
+            big_arr.push(activation.to_byte());
         }
-        big_arr_size += LAYER_SIZES[NUM_LAYERS - 1] * NUM_OUTPUTS;
-        big_arr_size *= 16; // 8 bytes for weight and 8 for offset
-        let mut big_arr = vec![0; big_arr_size];
 
-        let mut x = 0;
         for layer_conn in self.layer_conn_set.iter() {
-            for neuron in layer_conn {
-                for (weight, offset) in  neuron.lock().await.iter() {
-                    let weight_data = weight.to_be_bytes();
-                    for k in 0..8 {
-                        big_arr[x] = weight_data[k];
-                        x += 1;
-                    }
-                    let offset_data = offset.to_be_bytes();
-                    for k in 0..8 {
-                        big_arr[x] = offset_data[k];
-                        x += 1;
-                    }
+            for conns in layer_conn {
+                for (weight, offset) in conns.iter() {
+                    big_arr.extend_from_slice(&weight.to_be_bytes());
+                    big_arr.extend_from_slice(&offset.to_be_bytes());
                 }
             }
         }
@@ -173,3 +315,78 @@ impl Network {
         file.write_all(&big_arr).expect("Failed to save model to file!");
     }
 }
+
+// Randomly generate one layer's worth of (weight, offset) connections
+fn random_gen_neurons(out_size: usize, in_size: usize) -> Vec<Vec<(f64, f64)>> {
+    let mut rng = thread_rng();
+    let mut layer_conn = Vec::new();
+    for _ in 0..out_size {
+        let mut conns = Vec::new();
+        for _ in 0..in_size {
+            conns.push((rng.gen_range(-1.0..=1.0), rng.gen_range(-0.5..=0.5)));
+        }
+        layer_conn.push(conns);
+    }
+    layer_conn
+}
+
+// Unpack the raw game input into one float per bit, the only place bit-packing still happens
+fn unpack_input_bits(input_bits: &[u8]) -> Vec<f64> {
+    let mut bits = Vec::with_capacity(input_bits.len() * 8);
+    for byte in input_bits {
+        for i in 0..8 {
+            bits.push(((byte >> (7 - i)) & 0x01) as f64);
+        }
+    }
+    bits
+}
+
+// Feed a layer's real-valued input through its connections and squash the weighted sums
+fn layer_forward(
+        layer_conn: &[Vec<(f64, f64)>], input: &[f64], activation: Activation) -> Vec<f64> {
+    let mut layer_out = Vec::with_capacity(layer_conn.len());
+    for conns in layer_conn.iter() {
+        let mut sum = 0.0;
+        for (i, (weight, offset)) in conns.iter().enumerate() {
+            sum += weight * input[i] + offset;
+        }
+        layer_out.push(activation.apply(sum));
+    }
+    layer_out
+}
+
+// Trade connections between two layers, connection by connection
+fn neurons_trade(
+        layer_conn: &mut [Vec<(f64, f64)>], other_layer_conn: &mut [Vec<(f64, f64)>]) {
+    let mut rng = thread_rng();
+    for (conns, other_conns) in layer_conn.iter_mut().zip(other_layer_conn.iter_mut()) {
+        for (conn, other_conn) in conns.iter_mut().zip(other_conns.iter_mut()) {
+            if rng.gen_bool(TRAIT_SWAP_CHANCE) {
+                std::mem::swap(conn, other_conn);
+            }
+        }
+    }
+}
+
+// Mutate every connection in a layer, with chance/amount scaled by `boost`
+fn neurons_mutate(layer_conn: &mut [Vec<(f64, f64)>], boost: f64) {
+    let mut rng = thread_rng();
+    let weight_mutate_chance = (WEIGHT_MUTATE_CHANCE * boost).min(1.0);
+    let weight_mutate_amount = WEIGHT_MUTATE_AMOUNT * boost;
+    let weight_mutate_sigma = WEIGHT_MUTATE_SIGMA * boost;
+    let offset_mutate_chance = (OFFSET_MUTATE_CHANCE * boost).min(1.0);
+    let offset_mutate_amount = OFFSET_MUTATE_AMOUNT * boost;
+    let offset_mutate_sigma = OFFSET_MUTATE_SIGMA * boost;
+    for conns in layer_conn.iter_mut() {
+        for (weight, offset) in conns.iter_mut() {
+            if rng.gen_bool(weight_mutate_chance) {
+                let delta = mutate_delta(&mut rng, MUTATION_KIND, weight_mutate_amount, weight_mutate_sigma);
+                *weight = (*weight + delta).clamp(WEIGHT_RANGE.0, WEIGHT_RANGE.1);
+            }
+            if rng.gen_bool(offset_mutate_chance) {
+                let delta = mutate_delta(&mut rng, MUTATION_KIND, offset_mutate_amount, offset_mutate_sigma);
+                *offset = (*offset + delta).clamp(OFFSET_RANGE.0, OFFSET_RANGE.1);
+            }
+        }
+    }
+}
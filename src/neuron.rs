@@ -4,9 +4,11 @@
  */
 
 use rand::{
-    Rng, thread_rng
+    rngs::ThreadRng, Rng
+};
+use rand_distr::{
+    Distribution, Normal
 };
-use crate::data::RawGameInfo;
 
 pub const NEURON_ACTIVATION_THRESH: f64 = 0.60;
 pub const TRAIT_SWAP_CHANCE: f64 = 0.60;
@@ -15,159 +17,117 @@ pub const WEIGHT_MUTATE_AMOUNT: f64 = 0.015;
 pub const OFFSET_MUTATE_CHANCE: f64 = 0.10;
 pub const OFFSET_MUTATE_AMOUNT: f64 = 0.05;
 
-// A neuron doesn't actually exist, only the connections between them
-#[derive(Debug, Clone)]
-pub struct NeuronConnection {
-    pub weight: f64,
-    pub offset: f64
+/// Valid range a weight/offset is generated in and clamped back into after mutation, matching
+/// `random_gen_neurons`'s initial spread
+pub const WEIGHT_RANGE: (f64, f64) = (-1.0, 1.0);
+pub const OFFSET_RANGE: (f64, f64) = (-0.5, 0.5);
+
+/// Which kind of delta is drawn for a weight/offset mutation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MutationKind {
+    /// Uniform within +/- `amount`: a precise tweak is exactly as likely as a large jump.
+    /// Not currently wired to a CLI flag - flip MUTATION_KIND in neuron.rs to use it
+    #[allow(dead_code)]
+    Uniform,
+    /// Normal(0, sigma): most mutations are small, with an occasional large excursion - a
+    /// better local-search profile for fine-tuning late-generation populations
+    Gaussian
 }
 
-impl NeuronConnection {
-    pub async fn new_random() -> Self {
-        let mut rng = thread_rng();
-        Self {
-            weight: rng.gen_range(-1.0..=1.0),
-            offset: rng.gen_range(-0.5..=0.5)
-        }
-    }
+/// Which `MutationKind` weight/offset mutation uses
+pub const MUTATION_KIND: MutationKind = MutationKind::Gaussian;
+pub const WEIGHT_MUTATE_SIGMA: f64 = 0.0075;
+pub const OFFSET_MUTATE_SIGMA: f64 = 0.025;
 
-    pub async fn mutate(&mut self) {
-        let mut rng = thread_rng();
-        if rng.gen_bool(WEIGHT_MUTATE_CHANCE) {
-            self.weight = rng.gen_range(
-                (self.weight - WEIGHT_MUTATE_AMOUNT)..(self.weight + WEIGHT_MUTATE_AMOUNT)
-            );
-        }
-        let mut rng = thread_rng();
-        if rng.gen_bool(OFFSET_MUTATE_CHANCE) {
-            self.offset = rng.gen_range(
-                (self.offset - OFFSET_MUTATE_AMOUNT)..(self.offset + OFFSET_MUTATE_AMOUNT)
-            );
-        }
+/// Draw a mutation delta for `kind`, with `amount`/`sigma` already scaled by the caller's boost
+pub fn mutate_delta(rng: &mut ThreadRng, kind: MutationKind, amount: f64, sigma: f64) -> f64 {
+    match kind {
+        MutationKind::Uniform => rng.gen_range(-amount..=amount),
+        MutationKind::Gaussian => Normal::new(0.0, sigma).unwrap().sample(rng)
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct NeuronConnectionSet {
-    pub conns: Vec<NeuronConnection>
+/// Nonlinearity applied to a neuron's weighted sum before it's passed to the next layer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Activation {
+    /// Pass the weighted sum through unchanged
+    Identity,
+    /// 1 / (1 + e^-x)
+    Sigmoid,
+    /// x.tanh()
+    Tanh,
+    /// x.max(0.0)
+    ReLU,
+    /// The original hard cutoff at NEURON_ACTIVATION_THRESH
+    Step
 }
 
-impl NeuronConnectionSet {
-    /*
-     * Generate data
-     * I like the elegance of the spawn/map version, but it's slow
-     * The activations and rand_gen_neurons could also use something similar, but again, it's slower
-     */
-    async fn new_random(size: usize) -> Self {
-        let mut conns = Vec::new();
-        for _ in 0..size {
-            conns.push(NeuronConnection::new_random().await);
+impl Activation {
+    /// Squash a neuron's weighted sum according to this activation
+    pub fn apply(&self, sum: f64) -> f64 {
+        match self {
+            Activation::Identity => sum,
+            Activation::Sigmoid => 1.0 / (1.0 + (-sum).exp()),
+            Activation::Tanh => sum.tanh(),
+            Activation::ReLU => sum.max(0.0),
+            Activation::Step => if sum > NEURON_ACTIVATION_THRESH { 1.0 } else { 0.0 }
         }
-        Self {
-            conns
-        }
-
-        /*let handles: Vec<JoinHandle<NeuronConnection>> = vec![0.0; size].iter().map(|_| {
-            spawn(NeuronConnection::new_random())
-        }).collect();
-        try_join_all(handles).await.unwrap())*/
     }
 
-    // Get activated status of the actual neuron that falls in between the connections
-    pub async fn activated(&self, game: &RawGameInfo) -> bool {
-        let mut bit: u8 = 0; // Input bits are stored as, you guessed it, bits, so index by bit
-        let mut byte_ind: usize = 0; // After bit goes over 8, we increase the byte
-        let mut sum: f64 = 0.0;
-        for conn in self.conns.iter() {
-            let input = game.input_bits[byte_ind] >> (7 - bit) & 0x01;
-            sum += conn.weight * input as f64 + conn.offset;
-    
-            // Move throught the input array
-            bit += 1;
-            if bit == 8 {
-                byte_ind += 1;
-                bit = 0;
-            }
+    /// Quantize this activation's natural output range down to a single byte, used only on the
+    /// final layer's output so predicted scores land in the same byte range as `output_bits`
+    pub fn quantize(&self, activated: f64) -> u8 {
+        match self {
+            Activation::Sigmoid | Activation::Step => (activated.clamp(0.0, 1.0) * 255.0) as u8,
+            Activation::Tanh => (((activated.clamp(-1.0, 1.0) + 1.0) / 2.0) * 255.0) as u8,
+            Activation::Identity | Activation::ReLU => activated.clamp(0.0, 255.0) as u8
         }
-        sum > NEURON_ACTIVATION_THRESH
-    }
-
-    // Trade with another connection set
-    pub async fn trade_with(&mut self, other: &mut Self) {
-        self.conns.iter_mut().zip(other.conns.iter_mut()).for_each(|(conn, other_conn)| {
-            let mut rng = thread_rng();
-            if rng.gen_bool(TRAIT_SWAP_CHANCE) {
-                let old_conn = conn.clone();
-                *conn = other_conn.clone();
-                *other_conn = old_conn;
-            }
-        });
     }
 
-    // Mutate all neuron connections
-    pub async fn mutate_all(&mut self) {
-        for conn in self.conns.iter_mut() {
-            conn.mutate().await;
-        };
-    }
-}
-
-// This is essentially a mapping from one layer to another, so it's a connection
-#[derive(Debug, Clone)]
-pub struct NeuronConnectionMap {
-    pub map: Vec<NeuronConnectionSet>
-}
-
-impl NeuronConnectionMap {
-    /*
-    * Generating collections of data
-    * Note that doing it in parallel is significantly SLOWER than sequential due to overhead!
-    */
-    pub async fn new_random(size: usize, neuron_size: usize) -> Self {
-        let mut map = Vec::new();
-        for _ in 0..size {
-            map.push(NeuronConnectionSet::new_random(neuron_size).await);
-        }
-        Self {
-            map
+    /// Derivative of this activation w.r.t. its pre-activation sum, expressed in terms of the
+    /// already-computed `output = self.apply(sum)` the way backprop conventionally does it
+    pub fn derivative(&self, output: f64) -> f64 {
+        match self {
+            Activation::Identity => 1.0,
+            Activation::Sigmoid => output * (1.0 - output),
+            Activation::Tanh => 1.0 - output * output,
+            Activation::ReLU => if output > 0.0 { 1.0 } else { 0.0 },
+            Activation::Step => 0.0 // Flat almost everywhere; not a useful gradient signal
         }
     }
 
-    /*
-    * Get neuron activations for layer between connection
-    * Appears to be slower to use parallelism
-    */
-    pub async fn layer_activations(&self, game: &RawGameInfo) -> Vec<u8> {
-        let mut activates = Vec::new();
-        let mut curr_byte: u8 = 0; // Store results into packed bit arrays
-        let mut bit: u8 = 0;
-        for node in self.map.iter() {
-            if node.activated(&game).await {
-                curr_byte += 0x01 << (7 - bit);
-            }
-            bit += 1;
-
-            if bit == 8 {
-                activates.push(curr_byte);
-
-                curr_byte = 0;
-                bit = 0;
-            }
+    /// Map a target byte (the same range/format as `output_bits`) into this activation's
+    /// natural output range - the inverse of `quantize` - so backprop's error term compares
+    /// like with like
+    pub fn normalize_target(&self, byte: u8) -> f64 {
+        match self {
+            Activation::Sigmoid | Activation::Step => byte as f64 / 255.0,
+            Activation::Tanh => (byte as f64 / 255.0) * 2.0 - 1.0,
+            Activation::Identity | Activation::ReLU => byte as f64
         }
-        activates
     }
 
-    // Trade with another map
-    pub async fn trade_with(&mut self, other: &mut Self) {
-        for (set, other_set) in self.map.iter_mut().zip(other.map.iter_mut()) {
-            set.trade_with(other_set).await;
+    /// Tag used to persist this activation choice in a model file's header
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Activation::Identity => 0,
+            Activation::Sigmoid => 1,
+            Activation::Tanh => 2,
+            Activation::ReLU => 3,
+            Activation::Step => 4
         }
     }
 
-    // Mutate all neuron connections
-    pub async fn mutate_all(&mut self) {
-        for set in self.map.iter_mut() {
-            set.mutate_all().await;
+    /// Recover an Activation from a byte written by `to_byte`
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Activation::Identity),
+            1 => Some(Activation::Sigmoid),
+            2 => Some(Activation::Tanh),
+            3 => Some(Activation::ReLU),
+            4 => Some(Activation::Step),
+            _ => None
         }
     }
 }
+